@@ -11,21 +11,342 @@ use crate::{
 };
 use legion::{filter::*, prelude::*};
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    hash::{Hash, Hasher},
     marker::PhantomData,
-    ops::Deref,
+    ops::{Deref, Range},
 };
 pub const BIND_BUFFER_ALIGNMENT: u64 = 256;
 
+/// Hashes uniform bytes so callers can cheaply tell whether a previously
+/// uploaded value has actually changed before re-uploading it.
+fn hash_uniform_bytes(bytes: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Hashes `bytes` and compares it against `cache[key]`, updating the cache
+/// and returning `true` if it differs (i.e. the caller needs to re-upload),
+/// or returning `false` without touching the cache if it's unchanged.
+fn content_changed(cache: &mut HashMap<String, u64>, key: &str, bytes: &[u8]) -> bool {
+    let hash = hash_uniform_bytes(bytes);
+    if cache.get(key) == Some(&hash) {
+        return false;
+    }
+    cache.insert(key.to_string(), hash);
+    true
+}
+
+/// A free-list allocator that hands entities stable, `BIND_BUFFER_ALIGNMENT`-sized
+/// offsets into a dynamic uniform buffer. Offsets don't move once assigned, so
+/// callers can tell which entities are new (and therefore need their bytes
+/// uploaded) just by checking `offsets` before calling `allocate`.
+#[derive(Default)]
+struct UniformBufferSlotAllocator {
+    free_list: Vec<Range<u64>>,
+    offsets: HashMap<Entity, u64>,
+}
+
+impl UniformBufferSlotAllocator {
+    /// Returns `entity`'s stable offset, allocating a new slot if it doesn't
+    /// have one yet. `capacity` is the number of slots the backing buffer is
+    /// expected to hold; it is doubled (and the new space made available) if
+    /// the free list is exhausted.
+    fn allocate(&mut self, entity: Entity, capacity: &mut u64) -> u64 {
+        if let Some(offset) = self.offsets.get(&entity) {
+            return *offset;
+        }
+
+        if self.free_list.is_empty() {
+            // grow from whatever capacity was already handed out, not from
+            // `capacity.max(1)` -- that would skip slot 0 on the very first
+            // allocation, since it'd push a free range starting at slot 1
+            // instead of slot 0
+            let old_capacity = *capacity;
+            let new_capacity = old_capacity.max(1) * 2;
+            self.free_list.push(
+                (old_capacity * BIND_BUFFER_ALIGNMENT)..(new_capacity * BIND_BUFFER_ALIGNMENT),
+            );
+            *capacity = new_capacity;
+        }
+
+        // pop the lowest free offset
+        let mut range = self.free_list.remove(0);
+        let offset = range.start;
+        range.start += BIND_BUFFER_ALIGNMENT;
+        if !range.is_empty() {
+            self.free_list.insert(0, range);
+        }
+
+        self.offsets.insert(entity, offset);
+        offset
+    }
+
+    /// Reassigns the free-list bookkeeping for a slot from `old` to `new`,
+    /// used when a deduplicated slot's allocator-owner stops needing it but
+    /// other entities are still sharing its content, so the slot isn't
+    /// mistakenly freed out from under them.
+    fn rekey(&mut self, old: Entity, new: Entity) {
+        if let Some(offset) = self.offsets.remove(&old) {
+            self.offsets.insert(new, offset);
+        }
+    }
+
+    /// Returns `entity`'s slot to the free list, coalescing it with adjacent
+    /// free spans so the list doesn't fragment over time.
+    fn free(&mut self, entity: Entity) {
+        let offset = match self.offsets.remove(&entity) {
+            Some(offset) => offset,
+            None => return,
+        };
+        let range = offset..(offset + BIND_BUFFER_ALIGNMENT);
+
+        let idx = self
+            .free_list
+            .iter()
+            .position(|free_range| free_range.start >= range.start)
+            .unwrap_or_else(|| self.free_list.len());
+        self.free_list.insert(idx, range);
+
+        if idx + 1 < self.free_list.len() && self.free_list[idx].end == self.free_list[idx + 1].start
+        {
+            self.free_list[idx].end = self.free_list[idx + 1].end;
+            self.free_list.remove(idx + 1);
+        }
+        if idx > 0 && self.free_list[idx - 1].end == self.free_list[idx].start {
+            self.free_list[idx - 1].end = self.free_list[idx].end;
+            self.free_list.remove(idx);
+        }
+    }
+}
+
+/// Per-uniform-name bookkeeping for a dynamic uniform buffer: the backing
+/// `RenderResource` (once created), the slot capacity it was created with,
+/// the entities that contributed to it this frame, and the allocator handing
+/// out their stable offsets.
+#[derive(Default)]
+struct DynamicUniformBufferState {
+    resource: Option<RenderResource>,
+    /// number of `BIND_BUFFER_ALIGNMENT` slots the current `resource` was created with
+    realized_capacity: u64,
+    /// number of slots the allocator has handed out space for so far
+    capacity: u64,
+    entities: HashSet<Entity>,
+    allocator: UniformBufferSlotAllocator,
+    /// each entity's current offset, whether it owns that slot (via
+    /// `allocator`) or is sharing one found through `dedup_slots`
+    entity_offsets: HashMap<Entity, u64>,
+    /// content hash of the bytes last uploaded for each entity's slot
+    hashes: HashMap<Entity, u64>,
+    /// (deduplication mode only) content hash -> (shared slot holding that
+    /// data, entities currently sharing it), so entities with byte-identical
+    /// uniforms reuse one upload instead of getting a distinct slot each.
+    /// Reference-counted via the member set so a slot whose owner's bytes
+    /// change, or who despawns, isn't freed or overwritten while other
+    /// entities still depend on its contents.
+    dedup_slots: HashMap<u64, (u64, HashSet<Entity>)>,
+    /// (deduplication mode only) the reverse of `dedup_slots`: offset -> the
+    /// hash currently stored there, so a slot can be found and invalidated
+    /// by offset alone (e.g. when its occupant is being released)
+    slot_hashes: HashMap<u64, u64>,
+    /// bumped every time `resource` is (re)created, so entities bound against
+    /// an earlier generation can be told their binding is stale even though
+    /// their offset within the buffer hasn't changed
+    generation: u64,
+    /// the `generation` each entity's offset was last written into the
+    /// renderer's `DynamicUniformBufferInfo`
+    entity_generations: HashMap<Entity, u64>,
+}
+
+impl DynamicUniformBufferState {
+    /// Resolves `entity`'s stable offset, recording it in `added_offsets` if
+    /// it's newly assigned, and records the offset in `dirty_offsets` if its
+    /// slot needs (re)uploading: either it's a brand new, unique slot, or an
+    /// existing entity whose uniform bytes changed since last frame. Entities
+    /// that join an existing deduplicated slot are never marked dirty, since
+    /// whoever allocated that slot already uploaded the (identical) bytes.
+    fn resolve_slot(
+        &mut self,
+        deduplicate: bool,
+        entity: Entity,
+        uniform_bytes: &[u8],
+        added_offsets: &mut HashMap<Entity, u64>,
+        dirty_offsets: &mut HashMap<Entity, u64>,
+    ) {
+        let hash = hash_uniform_bytes(uniform_bytes);
+
+        if let Some(offset) = self.entity_offsets.get(&entity).cloned() {
+            if self.hashes.get(&entity) == Some(&hash) {
+                return;
+            }
+
+            // content changed. an entity that's the sole occupant of its
+            // slot (always true outside dedup mode) can keep its stable
+            // offset and just re-upload in place. one that's sharing a
+            // deduplicated slot with others can't: overwriting it with new
+            // content would corrupt what the other members think is there,
+            // so it has to leave the group and resolve a slot for its new
+            // hash instead, same as a brand new entity would.
+            let sole_occupant = match self.slot_hashes.get(&offset) {
+                Some(old_hash) => {
+                    let only_member = self
+                        .dedup_slots
+                        .get(old_hash)
+                        .map_or(true, |(_, members)| members.len() <= 1);
+                    only_member && !self.dedup_slots.contains_key(&hash)
+                }
+                None => true,
+            };
+
+            if sole_occupant {
+                if let Some(old_hash) = self.slot_hashes.remove(&offset) {
+                    self.dedup_slots.remove(&old_hash);
+                }
+                if deduplicate {
+                    let mut members = HashSet::new();
+                    members.insert(entity);
+                    self.dedup_slots.insert(hash, (offset, members));
+                    self.slot_hashes.insert(offset, hash);
+                }
+                self.hashes.insert(entity, hash);
+                dirty_offsets.insert(entity, offset);
+                return;
+            }
+
+            self.release_slot(entity, offset);
+            self.entity_offsets.remove(&entity);
+            self.hashes.remove(&entity);
+            // fall through and resolve a fresh slot below, exactly as if
+            // this were a brand new entity
+        }
+
+        let shared_offset = if deduplicate {
+            self.dedup_slots.get(&hash).map(|(offset, _)| *offset)
+        } else {
+            None
+        };
+
+        let offset = match shared_offset {
+            Some(offset) => {
+                self.dedup_slots.get_mut(&hash).unwrap().1.insert(entity);
+                offset
+            }
+            None => {
+                let offset = self.allocator.allocate(entity, &mut self.capacity);
+                if deduplicate {
+                    let mut members = HashSet::new();
+                    members.insert(entity);
+                    self.dedup_slots.insert(hash, (offset, members));
+                    self.slot_hashes.insert(offset, hash);
+                }
+                dirty_offsets.insert(entity, offset);
+                offset
+            }
+        };
+
+        self.entity_offsets.insert(entity, offset);
+        self.hashes.insert(entity, hash);
+        added_offsets.insert(entity, offset);
+    }
+
+    /// Releases `entity`'s claim on the slot at `offset`: drops it from a
+    /// deduplicated slot's member set (freeing the slot, or handing the
+    /// allocator's bookkeeping off to a remaining member, if `entity` was
+    /// the one the allocator knows about), or frees the slot outright
+    /// outside dedup mode.
+    fn release_slot(&mut self, entity: Entity, offset: u64) {
+        let hash = match self.slot_hashes.get(&offset) {
+            Some(hash) => *hash,
+            None => {
+                if self.allocator.offsets.get(&entity) == Some(&offset) {
+                    self.allocator.free(entity);
+                }
+                return;
+            }
+        };
+
+        let members = match self.dedup_slots.get_mut(&hash) {
+            Some((_, members)) => members,
+            None => return,
+        };
+        members.remove(&entity);
+
+        if members.is_empty() {
+            self.dedup_slots.remove(&hash);
+            self.slot_hashes.remove(&offset);
+            if self.allocator.offsets.get(&entity) == Some(&offset) {
+                self.allocator.free(entity);
+            }
+        } else if self.allocator.offsets.get(&entity) == Some(&offset) {
+            let successor = *members.iter().next().unwrap();
+            self.allocator.rekey(entity, successor);
+        }
+    }
+
+    /// Returns every entity whose offset needs to be (re-)written into the
+    /// current generation's `DynamicUniformBufferInfo`: entities newly
+    /// assigned this frame (`added_offsets`), plus -- when `resource` was
+    /// just recreated, bumping `generation` -- every other live entity,
+    /// since a fresh `DynamicUniformBufferInfo` starts with no offsets
+    /// recorded at all. Marks each returned entity as caught up to the
+    /// current generation.
+    fn entities_to_rebind(&mut self, added_offsets: &HashMap<Entity, u64>) -> Vec<(Entity, u64)> {
+        let rebind: Vec<(Entity, u64)> = self
+            .entity_offsets
+            .iter()
+            .filter(|(entity, _)| {
+                self.entity_generations.get(entity) != Some(&self.generation)
+                    || added_offsets.contains_key(entity)
+            })
+            .map(|(&entity, &offset)| (entity, offset))
+            .collect();
+
+        for &(entity, _) in &rebind {
+            self.entity_generations.insert(entity, self.generation);
+        }
+        rebind
+    }
+}
+
+// NOTE: staging buffers are not pooled. `Renderer`'s only write path is
+// `create_buffer_mapped`, which creates and maps a brand new buffer
+// atomically -- there is no way to write into a buffer that already exists,
+// so a staging buffer can't be reused for a second upload. Every upload
+// creates a mapped staging buffer and destroys it right after the copy.
+
+/// Uploads `bytes` to `offset` in `resource` via a freshly mapped staging buffer.
+fn upload_uniform_bytes(
+    renderer: &mut dyn Renderer,
+    resource: RenderResource,
+    offset: u64,
+    bytes: &[u8],
+) {
+    let staging_buffer =
+        renderer.create_buffer_mapped(bytes.len(), BufferUsage::COPY_SRC, &mut |mapped| {
+            mapped.copy_from_slice(bytes)
+        });
+    renderer.copy_buffer_to_buffer(staging_buffer, 0, resource, offset, bytes.len() as u64);
+    renderer.remove_buffer(staging_buffer);
+}
+
 pub struct UniformResourceProvider<T>
 where
     T: AsUniforms + Send + Sync + 'static,
 {
     _marker: PhantomData<T>,
-    // PERF: somehow remove this HashSet
-    uniform_buffer_info_resources:
-        HashMap<String, (Option<RenderResource>, usize, HashSet<Entity>)>,
+    uniform_buffer_info_resources: HashMap<String, DynamicUniformBufferState>,
+    // note: unlike uniform_buffer_info_resources, these entries have no generation
+    // counter -- a buffer here is created once per (handle, uniform_name) and never
+    // recreated/grown, so there's nothing for a generation to ever signal. The
+    // per-asset staleness counter chunk0-5 originally asked for here was tried in
+    // 083d9d8 and removed as dead code in a986ff9 for that reason.
     asset_resources: HashMap<Handle<T>, HashMap<String, RenderResource>>,
+    // content hash of the uniform bytes last uploaded per (asset handle, uniform name)
+    asset_uniform_hashes: HashMap<Handle<T>, HashMap<String, u64>>,
+    /// when true, entities whose dynamic uniform bytes are byte-identical
+    /// share a single buffer slot instead of each getting their own
+    deduplicate: bool,
     resource_query: Query<
         (Read<T>, Read<Renderable>),
         EntityFilterTuple<
@@ -54,12 +375,23 @@ where
         UniformResourceProvider {
             uniform_buffer_info_resources: Default::default(),
             asset_resources: Default::default(),
+            asset_uniform_hashes: Default::default(),
+            deduplicate: false,
             _marker: PhantomData,
             resource_query: <(Read<T>, Read<Renderable>)>::query(),
             handle_query: Some(<(Read<Handle<T>>, Read<Renderable>)>::query()),
         }
     }
 
+    /// When `deduplicate` is true, entities whose dynamic uniform bytes hash
+    /// identically (e.g. many entities sharing a model) share a single buffer
+    /// slot instead of each receiving their own, collapsing the number of
+    /// GPU writes down to the number of distinct uniform blocks.
+    pub fn with_deduplication(mut self, deduplicate: bool) -> Self {
+        self.deduplicate = deduplicate;
+        self
+    }
+
     fn update_asset_uniforms(
         &mut self,
         renderer: &mut dyn Renderer,
@@ -67,7 +399,6 @@ where
         resources: &Resources,
     ) {
         let handle_query = self.handle_query.take().unwrap();
-        // TODO: only update handle values when Asset value has changed
         if let Some(asset_storage) = resources.get::<AssetStorage<T>>() {
             for (entity, (handle, _renderable)) in handle_query.iter_entities(world) {
                 if let Some(uniforms) = asset_storage.get(&handle) {
@@ -100,17 +431,11 @@ where
             match uniform_info.bind_type {
                 BindType::Uniform { .. } => {
                     if dynamic_unforms {
-                        if let None = self.uniform_buffer_info_resources.get(uniform_info.name) {
-                            self.uniform_buffer_info_resources
-                                .insert(uniform_info.name.to_string(), (None, 0, HashSet::new()));
-                        }
-
-                        let (_resource, counts, entities) = self
+                        let state = self
                             .uniform_buffer_info_resources
-                            .get_mut(uniform_info.name)
-                            .unwrap();
-                        entities.insert(entity);
-                        *counts += 1;
+                            .entry(uniform_info.name.to_string())
+                            .or_insert_with(DynamicUniformBufferState::default);
+                        state.entities.insert(entity);
                     } else {
                         let handle = asset_handle.unwrap();
                         if let None = self.asset_resources.get(&handle) {
@@ -139,45 +464,32 @@ where
                             render_resource,
                         );
 
-                        let (tmp_buffer, tmp_buffer_size) = if let Some(uniform_bytes) =
+                        // only re-upload when the asset's uniform bytes have
+                        // actually changed since the last time we saw this handle
+                        let asset_hashes = self
+                            .asset_uniform_hashes
+                            .entry(handle)
+                            .or_insert_with(HashMap::new);
+
+                        if let Some(uniform_bytes) =
                             uniforms.get_uniform_bytes_ref(uniform_info.name)
                         {
-                            (
-                                renderer.create_buffer_mapped(
-                                    uniform_bytes.len(),
-                                    BufferUsage::COPY_SRC,
-                                    &mut |mapped| {
-                                        mapped.copy_from_slice(uniform_bytes);
-                                    },
-                                ),
-                                uniform_bytes.len(),
-                            )
+                            if !content_changed(asset_hashes, uniform_info.name, uniform_bytes) {
+                                continue;
+                            }
+
+                            upload_uniform_bytes(renderer, render_resource, 0, uniform_bytes);
                         } else if let Some(uniform_bytes) =
                             uniforms.get_uniform_bytes(uniform_info.name)
                         {
-                            (
-                                renderer.create_buffer_mapped(
-                                    uniform_bytes.len(),
-                                    BufferUsage::COPY_SRC,
-                                    &mut |mapped| {
-                                        mapped.copy_from_slice(&uniform_bytes);
-                                    },
-                                ),
-                                uniform_bytes.len(),
-                            )
+                            if !content_changed(asset_hashes, uniform_info.name, &uniform_bytes) {
+                                continue;
+                            }
+
+                            upload_uniform_bytes(renderer, render_resource, 0, &uniform_bytes);
                         } else {
                             panic!("failed to get data from uniform: {}", uniform_info.name);
-                        };
-
-                        renderer.copy_buffer_to_buffer(
-                            tmp_buffer,
-                            0,
-                            render_resource,
-                            0,
-                            tmp_buffer_size as u64,
-                        );
-
-                        renderer.remove_buffer(tmp_buffer);
+                        }
                     }
                 }
                 BindType::SampledTexture { .. } => {
@@ -232,90 +544,138 @@ where
     }
 
     fn setup_dynamic_uniform_buffers(&mut self, renderer: &mut dyn Renderer, world: &World) {
-        // allocate uniform buffers
-        for (name, (resource, count, _entities)) in self.uniform_buffer_info_resources.iter_mut() {
-            let count = *count as u64;
-            if let Some(resource) = resource {
-                let mut info = renderer
-                    .get_dynamic_uniform_buffer_info_mut(*resource)
-                    .unwrap();
-                info.count = count;
-                continue;
+        let deduplicate = self.deduplicate;
+        for (name, state) in self.uniform_buffer_info_resources.iter_mut() {
+            // drop bookkeeping for entities that disappeared this frame,
+            // returning their slot to the free list if they owned one (rather
+            // than merely sharing a deduplicated slot with other entities)
+            let freed_entities: Vec<Entity> = state
+                .entity_offsets
+                .keys()
+                .cloned()
+                .filter(|entity| !state.entities.contains(entity))
+                .collect();
+            for entity in freed_entities {
+                if let Some(offset) = state.entity_offsets.get(&entity).cloned() {
+                    state.release_slot(entity, offset);
+                }
+                state.entity_offsets.remove(&entity);
+                state.hashes.remove(&entity);
+                state.entity_generations.remove(&entity);
             }
 
-            // allocate enough space for twice as many entities as there are currently;
-            let capacity = count * 2;
-            let size = BIND_BUFFER_ALIGNMENT * capacity;
-            let created_resource =
-                renderer.create_buffer(size, BufferUsage::COPY_DST | BufferUsage::UNIFORM);
-
-            let mut info = DynamicUniformBufferInfo::new();
-            info.count = count;
-            info.capacity = capacity;
-            renderer.add_dynamic_uniform_buffer_info(created_resource, info);
-            *resource = Some(created_resource);
-            renderer
-                .get_render_resources_mut()
-                .set_named_resource(name, created_resource);
-        }
+            // resolve a stable offset for every entity that doesn't have one
+            // yet (in dedup mode, entities whose bytes hash the same as an
+            // already-seen block share that block's offset instead of getting
+            // their own), and note which offsets need (re)uploading: a brand
+            // new slot, or an existing entity whose content hash changed.
+            let mut added_offsets = HashMap::new();
+            let mut dirty_offsets = HashMap::new();
+            for (entity, (uniforms, _renderable)) in self.resource_query.iter_entities(world) {
+                if !state.entities.contains(&entity) {
+                    continue;
+                }
 
-        // copy entity uniform data to buffers
-        for (name, (resource, _count, entities)) in self.uniform_buffer_info_resources.iter() {
-            let resource = resource.unwrap();
-            let size = {
-                // TODO: this lookup isn't needed anymore?
-                let info = renderer.get_dynamic_uniform_buffer_info(resource).unwrap();
-                BIND_BUFFER_ALIGNMENT * info.count
+                if let Some(uniform_bytes) = uniforms.get_uniform_bytes_ref(&name) {
+                    state.resolve_slot(
+                        deduplicate,
+                        entity,
+                        uniform_bytes,
+                        &mut added_offsets,
+                        &mut dirty_offsets,
+                    );
+                } else if let Some(uniform_bytes) = uniforms.get_uniform_bytes(&name) {
+                    state.resolve_slot(
+                        deduplicate,
+                        entity,
+                        &uniform_bytes,
+                        &mut added_offsets,
+                        &mut dirty_offsets,
+                    );
+                }
+            }
+
+            // `count`/buffer size track the number of distinct slots handed
+            // out by `allocator`, which in dedup mode is the number of
+            // distinct uniform blocks rather than the number of entities.
+            let slot_count = state.allocator.offsets.len() as u64;
+            let size = BIND_BUFFER_ALIGNMENT * state.capacity;
+            let resource = match state.resource {
+                Some(old_resource) if state.capacity > state.realized_capacity => {
+                    // the allocator outgrew the backing buffer; replace it with a
+                    // larger one and migrate the bytes that were already uploaded
+                    let old_size = BIND_BUFFER_ALIGNMENT * state.realized_capacity;
+                    let new_resource = renderer.create_buffer(
+                        size,
+                        BufferUsage::COPY_SRC | BufferUsage::COPY_DST | BufferUsage::UNIFORM,
+                    );
+                    renderer.copy_buffer_to_buffer(old_resource, 0, new_resource, 0, old_size);
+                    renderer.remove_buffer(old_resource);
+                    renderer.add_dynamic_uniform_buffer_info(
+                        new_resource,
+                        DynamicUniformBufferInfo::new(),
+                    );
+                    renderer
+                        .get_render_resources_mut()
+                        .set_named_resource(name, new_resource);
+                    state.resource = Some(new_resource);
+                    state.realized_capacity = state.capacity;
+                    // the old resource is gone and `DynamicUniformBufferInfo::new()`
+                    // starts empty, so every entity's binding needs to be re-emitted
+                    // into it, not just the ones added this frame
+                    state.generation += 1;
+                    new_resource
+                }
+                Some(resource) => resource,
+                None => {
+                    let created_resource = renderer.create_buffer(
+                        size,
+                        BufferUsage::COPY_SRC | BufferUsage::COPY_DST | BufferUsage::UNIFORM,
+                    );
+                    renderer.add_dynamic_uniform_buffer_info(
+                        created_resource,
+                        DynamicUniformBufferInfo::new(),
+                    );
+                    renderer
+                        .get_render_resources_mut()
+                        .set_named_resource(name, created_resource);
+                    state.resource = Some(created_resource);
+                    state.realized_capacity = state.capacity;
+                    state.generation += 1;
+                    created_resource
+                }
             };
 
-            let alignment = BIND_BUFFER_ALIGNMENT as usize;
-            let mut offset = 0usize;
-            let info = renderer
-                .get_dynamic_uniform_buffer_info_mut(resource)
-                .unwrap();
-            for (entity, _) in self.resource_query.iter_entities(world) {
-                if !entities.contains(&entity) {
-                    continue;
+            {
+                // re-emit every entity whose binding hasn't been written into
+                // this generation of `resource` yet, not just ones newly
+                // assigned this frame, so a buffer recreation can't leave
+                // stale (or missing) offsets behind for entities that were
+                // already stable
+                let rebind = state.entities_to_rebind(&added_offsets);
+                let info = renderer
+                    .get_dynamic_uniform_buffer_info_mut(resource)
+                    .unwrap();
+                info.count = slot_count;
+                info.capacity = state.capacity;
+                for (entity, offset) in rebind {
+                    info.offsets.insert(entity, offset as u32);
                 }
-                // TODO: check if index has changed. if it has, then entity should be updated
-                // TODO: only mem-map entities if their data has changed
-                // PERF: These hashmap inserts are pretty expensive (10 fps for 10000 entities)
-                info.offsets.insert(entity, offset as u32);
-                // TODO: try getting ref first
-                offset += alignment;
             }
 
-            let mapped_buffer_resource = renderer.create_buffer_mapped(
-                size as usize,
-                BufferUsage::COPY_SRC,
-                &mut |mapped| {
-                    let alignment = BIND_BUFFER_ALIGNMENT as usize;
-                    let mut offset = 0usize;
-                    for (entity, (uniforms, _renderable)) in
-                        self.resource_query.iter_entities(world)
-                    {
-                        if !entities.contains(&entity) {
-                            continue;
-                        }
-                        // TODO: check if index has changed. if it has, then entity should be updated
-                        // TODO: only mem-map entities if their data has changed
-                        if let Some(uniform_bytes) = uniforms.get_uniform_bytes_ref(&name) {
-                            mapped[offset..(offset + uniform_bytes.len())]
-                                .copy_from_slice(uniform_bytes);
-                            offset += alignment;
-                        } else if let Some(uniform_bytes) = uniforms.get_uniform_bytes(&name) {
-                            mapped[offset..(offset + uniform_bytes.len())]
-                                .copy_from_slice(uniform_bytes.as_slice());
-                            offset += alignment;
-                        }
-                    }
-                },
-            );
-
-            renderer.copy_buffer_to_buffer(mapped_buffer_resource, 0, resource, 0, size);
+            // upload bytes only for the slots resolve_slot flagged as dirty
+            for (entity, (uniforms, _renderable)) in self.resource_query.iter_entities(world) {
+                let offset = match dirty_offsets.get(&entity) {
+                    Some(offset) => *offset,
+                    None => continue,
+                };
 
-            // TODO: uncomment this to free resource?
-            renderer.remove_buffer(mapped_buffer_resource);
+                if let Some(uniform_bytes) = uniforms.get_uniform_bytes_ref(&name) {
+                    upload_uniform_bytes(renderer, resource, offset, uniform_bytes);
+                } else if let Some(uniform_bytes) = uniforms.get_uniform_bytes(&name) {
+                    upload_uniform_bytes(renderer, resource, offset, &uniform_bytes);
+                }
+            }
         }
     }
 }
@@ -335,17 +695,15 @@ where
 
     fn update(&mut self, renderer: &mut dyn Renderer, world: &mut World, resources: &Resources) {
         let query = <(Read<T>, Read<Renderable>)>::query();
-        // TODO: this breaks down in multiple ways:
         // (SOLVED 1) resource_info will be set after the first run so this won't update.
-        // (2) if we create new buffers, the old bind groups will be invalid
-
-        // reset all uniform buffer info counts
-        for (_name, (resource, count, _entities)) in self.uniform_buffer_info_resources.iter_mut() {
-            renderer
-                .get_dynamic_uniform_buffer_info_mut(resource.unwrap())
-                .unwrap()
-                .count = 0;
-            *count = 0;
+        // (SOLVED 2) if we create new buffers, the old bind groups will be invalid --
+        // `DynamicUniformBufferState::generation` is bumped on recreation and every
+        // entity's binding is re-emitted into the new `DynamicUniformBufferInfo`.
+
+        // reset per-name entity membership for this frame; the allocator
+        // itself persists stable offsets across frames
+        for (_name, state) in self.uniform_buffer_info_resources.iter_mut() {
+            state.entities.clear();
         }
 
         self.update_asset_uniforms(renderer, world, resources);
@@ -378,4 +736,215 @@ where
             }
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spawn_entities(world: &mut World, count: usize) -> Vec<Entity> {
+        world.insert((), (0..count).map(|_| (0u8,))).to_vec()
+    }
+
+    #[test]
+    fn allocator_reuses_freed_slots_before_growing() {
+        let universe = Universe::new();
+        let mut world = universe.create_world();
+        let entities = spawn_entities(&mut world, 3);
+
+        let mut allocator = UniformBufferSlotAllocator::default();
+        let mut capacity = 0u64;
+
+        let a = allocator.allocate(entities[0], &mut capacity);
+        let b = allocator.allocate(entities[1], &mut capacity);
+        assert_eq!(a, 0);
+        assert_eq!(b, BIND_BUFFER_ALIGNMENT);
+        assert_eq!(capacity, 2);
+
+        allocator.free(entities[0]);
+        let c = allocator.allocate(entities[2], &mut capacity);
+        // the freed slot is reused instead of growing the buffer again
+        assert_eq!(c, 0);
+        assert_eq!(capacity, 2);
+    }
+
+    #[test]
+    fn allocator_coalesces_adjacent_free_spans() {
+        let universe = Universe::new();
+        let mut world = universe.create_world();
+        let entities = spawn_entities(&mut world, 2);
+
+        let mut allocator = UniformBufferSlotAllocator::default();
+        let mut capacity = 0u64;
+        allocator.allocate(entities[0], &mut capacity);
+        allocator.allocate(entities[1], &mut capacity);
+
+        allocator.free(entities[0]);
+        allocator.free(entities[1]);
+
+        // both freed slots (the whole buffer, since capacity is 2) should
+        // coalesce into a single free span rather than two fragments
+        assert_eq!(allocator.free_list.len(), 1);
+        assert_eq!(allocator.free_list[0], 0..(capacity * BIND_BUFFER_ALIGNMENT));
+    }
+
+    #[test]
+    fn dedup_owner_diverges_instead_of_overwriting_shared_slot() {
+        let universe = Universe::new();
+        let mut world = universe.create_world();
+        let entities = spawn_entities(&mut world, 2);
+        let (owner, sharer) = (entities[0], entities[1]);
+
+        let mut state = DynamicUniformBufferState::default();
+        let mut added = HashMap::new();
+        let mut dirty = HashMap::new();
+
+        state.resolve_slot(true, owner, b"aaaa", &mut added, &mut dirty);
+        state.resolve_slot(true, sharer, b"aaaa", &mut added, &mut dirty);
+        let shared_offset = state.entity_offsets[&owner];
+        assert_eq!(state.entity_offsets[&sharer], shared_offset);
+
+        added.clear();
+        dirty.clear();
+        state.resolve_slot(true, owner, b"bbbb", &mut added, &mut dirty);
+
+        // the owner's changed bytes must land in a new slot, not the one
+        // the sharer still believes holds "aaaa"
+        assert_ne!(state.entity_offsets[&owner], shared_offset);
+        assert_eq!(state.entity_offsets[&sharer], shared_offset);
+    }
+
+    #[test]
+    fn dedup_slot_is_freed_only_once_every_sharer_is_gone() {
+        let universe = Universe::new();
+        let mut world = universe.create_world();
+        let entities = spawn_entities(&mut world, 2);
+        let (owner, sharer) = (entities[0], entities[1]);
+
+        let mut state = DynamicUniformBufferState::default();
+        let mut added = HashMap::new();
+        let mut dirty = HashMap::new();
+        state.resolve_slot(true, owner, b"aaaa", &mut added, &mut dirty);
+        state.resolve_slot(true, sharer, b"aaaa", &mut added, &mut dirty);
+        let shared_offset = state.entity_offsets[&owner];
+        let hash = hash_uniform_bytes(b"aaaa");
+
+        state.release_slot(owner, shared_offset);
+        // the allocator's bookkeeping must move to the remaining sharer
+        // rather than freeing a slot that's still in use
+        assert_eq!(state.allocator.offsets.get(&sharer), Some(&shared_offset));
+        assert!(state.dedup_slots.contains_key(&hash));
+
+        state.release_slot(sharer, shared_offset);
+        assert!(!state.dedup_slots.contains_key(&hash));
+        assert!(state.allocator.offsets.get(&sharer).is_none());
+    }
+
+    #[test]
+    fn resolve_slot_skips_dirty_flag_when_bytes_are_unchanged() {
+        let universe = Universe::new();
+        let mut world = universe.create_world();
+        let entity = spawn_entities(&mut world, 1)[0];
+
+        let mut state = DynamicUniformBufferState::default();
+        let mut added = HashMap::new();
+        let mut dirty = HashMap::new();
+        state.resolve_slot(false, entity, b"same", &mut added, &mut dirty);
+        assert!(dirty.contains_key(&entity));
+
+        added.clear();
+        dirty.clear();
+        state.resolve_slot(false, entity, b"same", &mut added, &mut dirty);
+        // identical bytes on an already-resolved entity shouldn't re-flag
+        // the slot for upload
+        assert!(!dirty.contains_key(&entity));
+        assert!(!added.contains_key(&entity));
+    }
+
+    #[test]
+    fn resolve_slot_flags_dirty_when_bytes_change() {
+        let universe = Universe::new();
+        let mut world = universe.create_world();
+        let entity = spawn_entities(&mut world, 1)[0];
+
+        let mut state = DynamicUniformBufferState::default();
+        let mut added = HashMap::new();
+        let mut dirty = HashMap::new();
+        state.resolve_slot(false, entity, b"aaaa", &mut added, &mut dirty);
+        let offset = state.entity_offsets[&entity];
+
+        added.clear();
+        dirty.clear();
+        state.resolve_slot(false, entity, b"bbbb", &mut added, &mut dirty);
+        assert_eq!(dirty.get(&entity), Some(&offset));
+    }
+
+    #[test]
+    fn content_changed_skips_when_bytes_are_unchanged() {
+        let mut cache = HashMap::new();
+        assert!(content_changed(&mut cache, "a_uniform", b"same"));
+        assert!(!content_changed(&mut cache, "a_uniform", b"same"));
+    }
+
+    #[test]
+    fn content_changed_flags_when_bytes_change() {
+        let mut cache = HashMap::new();
+        assert!(content_changed(&mut cache, "a_uniform", b"aaaa"));
+        assert!(content_changed(&mut cache, "a_uniform", b"bbbb"));
+    }
+
+    #[test]
+    fn entities_to_rebind_is_a_no_op_within_the_same_generation() {
+        let universe = Universe::new();
+        let mut world = universe.create_world();
+        let entities = spawn_entities(&mut world, 2);
+
+        let mut state = DynamicUniformBufferState::default();
+        let mut added = HashMap::new();
+        let mut dirty = HashMap::new();
+        state.resolve_slot(false, entities[0], b"aaaa", &mut added, &mut dirty);
+        state.resolve_slot(false, entities[1], b"bbbb", &mut added, &mut dirty);
+        // simulate the buffer already having been created this generation,
+        // with both entities already written into its info.offsets
+        state.entities_to_rebind(&added);
+
+        added.clear();
+        // neither entity changed or was newly added this frame, and the
+        // buffer didn't grow, so nothing should need rewriting
+        assert!(state.entities_to_rebind(&added).is_empty());
+    }
+
+    #[test]
+    fn entities_to_rebind_covers_every_live_entity_after_a_generation_bump() {
+        let universe = Universe::new();
+        let mut world = universe.create_world();
+        let entities = spawn_entities(&mut world, 3);
+
+        let mut state = DynamicUniformBufferState::default();
+        let mut added = HashMap::new();
+        let mut dirty = HashMap::new();
+        state.resolve_slot(false, entities[0], b"aaaa", &mut added, &mut dirty);
+        state.resolve_slot(false, entities[1], b"bbbb", &mut added, &mut dirty);
+        // the first generation's buffer creation writes both into info.offsets
+        state.entities_to_rebind(&added);
+
+        // a third entity joins and forces the backing buffer to grow, which
+        // recreates its DynamicUniformBufferInfo empty and bumps generation
+        added.clear();
+        dirty.clear();
+        state.resolve_slot(false, entities[2], b"cccc", &mut added, &mut dirty);
+        state.generation += 1;
+
+        let rebind: HashMap<Entity, u64> = state.entities_to_rebind(&added).into_iter().collect();
+        // every live entity must be re-emitted into the fresh info, not just
+        // the one added this frame
+        assert_eq!(rebind.len(), 3);
+        assert!(rebind.contains_key(&entities[0]));
+        assert!(rebind.contains_key(&entities[1]));
+        assert!(rebind.contains_key(&entities[2]));
+
+        // and the next call, still in the same (new) generation, is a no-op
+        added.clear();
+        assert!(state.entities_to_rebind(&added).is_empty());
+    }
 }
\ No newline at end of file